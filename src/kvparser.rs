@@ -1,30 +1,107 @@
 use std::{
-    fs::File, io::{self, BufRead},
-    path::{Path, PathBuf},
-    collections::{LinkedList, HashMap},
-    thread, sync::Arc,
+    fs::File, io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    collections::LinkedList,
+    thread, sync::{Arc, Mutex},
     marker::{Send, Sync},
 };
 use memmap::Mmap;
 use deque::{Stealer, Stolen};
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use tempfile::NamedTempFile;
+use indexmap::IndexMap;
+use std::fmt;
 
 pub trait Parsable {
     type Output;
-    fn new(key_values: HashMap<String, String>) -> Option<Self::Output>;
+    fn new(key_values: IndexMap<String, String>) -> Option<Self::Output>;
+}
+
+/// Error raised while parsing a key-value file instead of surfacing an opaque
+/// panic. An invalid UTF-8 error carries a byte offset into the input, which
+/// points at *which* stanza of a large `Packages` file was malformed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The input could not be read.
+    Io(io::Error),
+    /// The file could not be memory-mapped.
+    Mmap(io::Error),
+    /// A byte sequence at the given offset was not valid UTF-8.
+    InvalidUtf8 { offset: usize },
+    /// A worker thread panicked before returning its models.
+    WorkerPanicked,
+    /// `parse` was called more than once on a reader-backed parser, whose
+    /// stream can only be consumed a single time.
+    ReaderConsumed,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "failed to read input: {}", err),
+            ParseError::Mmap(err) => write!(f, "failed to memory-map input: {}", err),
+            ParseError::InvalidUtf8 { offset } => {
+                write!(f, "invalid UTF-8 at byte offset {}", offset)
+            }
+            ParseError::WorkerPanicked => write!(f, "a parser worker thread panicked"),
+            ParseError::ReaderConsumed => {
+                write!(f, "the reader-backed parser has already been consumed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(err) | ParseError::Mmap(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+/// How the parser feeds chunks to the workers. `Mmap` maps the backing file
+/// and hands workers byte ranges; `Buffered` streams the input and hands
+/// workers owned chunks, which is the only option for stdin and pipes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    Mmap,
+    Buffered,
+}
+
+enum Input {
+    // A real file on disk, which can be memory-mapped
+    Path { file: File },
+    // An arbitrary stream (stdin, a pipe, ...) that can only be read once
+    Reader(Mutex<Option<Box<dyn BufRead + Send>>>),
 }
 
 pub struct Parser {
-    file_path: PathBuf,
-    file: File,
+    input: Input,
+    mode: ReadMode,
+    // When the source was compressed it is decompressed into this temp file,
+    // which is held for the parser's lifetime and deleted when it is dropped
+    _decompressed: Option<NamedTempFile>,
 }
 
 enum ChunkWorkerState {
+    // A `(start, end)` range into the shared memory map
     Process(usize, usize),
+    // An owned chunk produced by the buffered reader, with its byte offset
+    // into the stream so UTF-8 errors carry an absolute position
+    ProcessOwned(usize, Vec<u8>),
     Quit,
 }
 
 struct ChunkWorker {
-    file: Mmap,
+    file: Option<Arc<Mmap>>,
     stealer: Stealer<ChunkWorkerState>,
 }
 
@@ -33,7 +110,7 @@ impl Parser {
     pub fn new<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
 
         // If file is not found or user has no permissions throw an error
-        let file = File::open(file_path.as_ref())?;
+        let mut file = File::open(file_path.as_ref())?;
         let metadata = file.metadata()?;
 
         // Also throw error if file is empty
@@ -41,12 +118,72 @@ impl Parser {
             return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
         }
 
+        // Repo indices almost always ship compressed (`Packages.gz`, `Packages.xz`).
+        // Sniff the magic bytes and, when compressed, stream-decompress into a temp
+        // file so the mmap/chunk logic below can run against plain bytes.
+        if let Some(decoder) = Self::decompressor(&mut file)? {
+            let mut decoder = decoder;
+            let mut temp = NamedTempFile::new()?;
+            io::copy(&mut decoder, temp.as_file_mut())?;
+            temp.as_file_mut().sync_all()?;
+
+            let file = temp.reopen()?;
+            return Ok(Self {
+                input: Input::Path { file },
+                mode: ReadMode::Mmap,
+                _decompressed: Some(temp),
+            });
+        }
+
         Ok(Self {
-            file_path: file_path.as_ref().to_path_buf(),
-            file,
+            input: Input::Path { file },
+            mode: ReadMode::Mmap,
+            _decompressed: None,
         })
     }
 
+    /// Creates a parser over an arbitrary buffered stream instead of a file.
+    ///
+    /// This is the only usable construction path for inputs that cannot be
+    /// memory-mapped — stdin, pipes, or zero-length special files — and lets
+    /// the crate be dropped into shell pipelines such as
+    /// `apt-cache dumpavail | twackup ...`. It always reads in [`ReadMode::Buffered`].
+    pub fn from_reader<R: BufRead + Send + 'static>(reader: R) -> Self {
+        Self {
+            input: Input::Reader(Mutex::new(Some(Box::new(reader)))),
+            mode: ReadMode::Buffered,
+            _decompressed: None,
+        }
+    }
+
+    /// Forces the read mode, e.g. to request buffered reading for a file that
+    /// would otherwise be memory-mapped. A reader-backed parser is always
+    /// buffered and ignores an attempt to switch it to [`ReadMode::Mmap`].
+    pub fn with_read_mode(mut self, mode: ReadMode) -> Self {
+        if let Input::Path { .. } = self.input {
+            self.mode = mode;
+        }
+        self
+    }
+
+    /// Sniffs the leading magic bytes of `file` and, when it is a compressed
+    /// stream we understand, returns a reader that yields the decompressed bytes.
+    /// The file cursor is rewound to the start before the reader is built.
+    fn decompressor(file: &mut File) -> io::Result<Option<Box<dyn Read>>> {
+        let mut magic = [0u8; 6];
+        let read = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let file = file.try_clone()?;
+        if read >= 2 && magic[0] == 0x1f && magic[1] == 0x8b {
+            Ok(Some(Box::new(MultiGzDecoder::new(file))))
+        } else if read >= 6 && magic[..6] == [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00] {
+            Ok(Some(Box::new(XzDecoder::new_multi_decoder(file))))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// This method will parse file with key-value syntax on separate lines
     /// and call handler for each found block
     ///
@@ -59,20 +196,63 @@ impl Parser {
     /// Package: com.example.my.other.package
     /// Name: My Other Package
     /// ```
-    pub fn parse<P: Parsable<Output = P> + 'static + Send + Sync>(&self) -> Vec<Arc<P>> {
-        let mut last_is_nl = true;
-        let mut last_nl_pos = 0;
-        let mut cur_position = 0;
+    pub fn parse<P: Parsable<Output = P> + 'static + Send + Sync>(&self) -> Result<Vec<Arc<P>>, ParseError> {
+        // Map the file exactly once in the main thread and share the single
+        // mapping with every worker through an `Arc`, so there are no redundant
+        // `open`/`mmap` syscalls and all threads observe identical bytes.
+        let shared_mmap = match (&self.input, self.mode) {
+            (Input::Path { file, .. }, ReadMode::Mmap) => {
+                // Load file in memory with mmap kernel feature
+                let mmap = unsafe { Mmap::map(file) }.map_err(ParseError::Mmap)?;
+                Some(Arc::new(mmap))
+            }
+            _ => None,
+        };
 
         let mut workers = Vec::new();
         let (workq, stealer) = deque::new();
         for _ in 0..num_cpus::get() {
-            let worker = ChunkWorker::new(&self.file_path, stealer.clone());
+            // Buffered workers hold no mapping — they parse owned chunks
+            let worker = ChunkWorker::new(shared_mmap.clone(), stealer.clone());
             workers.push(thread::spawn(move || worker.run()));
         }
 
-        // Load file in memory with mmap kernel feature
-        let fmmap = unsafe { Mmap::map(&self.file).unwrap()  };
+        match (&self.input, self.mode) {
+            (Input::Path { .. }, ReadMode::Mmap) => {
+                self.dispatch_mmap(shared_mmap.as_ref().unwrap(), &workq);
+            }
+            (Input::Path { file, .. }, ReadMode::Buffered) => {
+                self.dispatch_reader(&mut BufReader::new(file.try_clone()?), &workq)?;
+            }
+            (Input::Reader(reader), _) => {
+                let mut reader = reader.lock().unwrap().take()
+                    .ok_or(ParseError::ReaderConsumed)?;
+                self.dispatch_reader(&mut reader, &workq)?;
+            }
+        }
+
+        for _ in 0..workers.len() {
+            workq.push(ChunkWorkerState::Quit);
+        }
+
+        // Propagate a worker panic or parse failure through the join instead
+        // of unwrapping and tearing down the whole process.
+        let mut models = Vec::new();
+        for worker in workers {
+            let parsed = worker.join().map_err(|_| ParseError::WorkerPanicked)?;
+            models.extend(parsed?.iter().cloned());
+        }
+
+        Ok(models)
+    }
+
+    /// Scans the memory map for blank-line block boundaries and hands each
+    /// block to the workers as a `(start, end)` range.
+    fn dispatch_mmap(&self, fmmap: &Mmap, workq: &deque::Worker<ChunkWorkerState>) {
+        let mut last_is_nl = true;
+        let mut last_nl_pos = 0;
+        let mut cur_position = 0;
+
         // And iterate for all bytes in file
         for byte in fmmap.iter() {
             cur_position += 1;
@@ -85,73 +265,138 @@ impl Parser {
             last_is_nl = nl;
         }
 
-        for _ in 0..workers.len() {
-            workq.push(ChunkWorkerState::Quit);
+        // Flush the final stanza when the input does not end in a blank line,
+        // mirroring `dispatch_reader`'s `read == 0` flush.
+        if last_nl_pos < cur_position {
+            workq.push(ChunkWorkerState::Process(last_nl_pos, cur_position));
         }
+    }
 
-        let mut models = Vec::new();
-        for worker in workers {
-            models.extend(worker.join().unwrap().iter().cloned())
+    /// Scans a stream for blank-line block boundaries and dispatches each
+    /// block to the workers as an owned buffer. For `\n`-terminated input this
+    /// yields the same stanzas as `dispatch_mmap`; unlike `dispatch_mmap`, a
+    /// line holding only `\r\n` also closes a block here.
+    fn dispatch_reader<R: BufRead>(
+        &self,
+        reader: &mut R,
+        workq: &deque::Worker<ChunkWorkerState>,
+    ) -> Result<(), ParseError> {
+        let mut chunk = Vec::new();
+        let mut line = Vec::new();
+        // Running byte offset into the stream and the offset where the current
+        // chunk started, so a UTF-8 error is reported at its absolute position.
+        let mut offset = 0;
+        let mut chunk_start = 0;
+
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            // A blank line closes the current block
+            let blank = read == 0 || line.iter().all(|b| *b == b'\n' || *b == b'\r');
+            if blank && !chunk.is_empty() {
+                workq.push(ChunkWorkerState::ProcessOwned(chunk_start, std::mem::take(&mut chunk)));
+            } else if !blank {
+                if chunk.is_empty() {
+                    chunk_start = offset;
+                }
+                chunk.extend_from_slice(&line);
+            }
+            offset += read;
+            if read == 0 {
+                break;
+            }
         }
 
-        return models;
+        Ok(())
     }
 }
 
 impl ChunkWorker {
     /// Prepares environment and creates parser instance
-    fn new<P: AsRef<Path>>(file_path: P, stealer: Stealer<ChunkWorkerState>) -> Self {
-        let file = unsafe { Mmap::map(&File::open(file_path).unwrap()).unwrap() };
+    fn new(file: Option<Arc<Mmap>>, stealer: Stealer<ChunkWorkerState>) -> Self {
         Self { file, stealer }
     }
 
     /// Parses chunk to model
-    fn run<P: Parsable + Parsable<Output = P>>(&self) -> Vec<Arc<P>> {
+    fn run<P: Parsable + Parsable<Output = P>>(&self) -> Result<Vec<Arc<P>>, ParseError> {
         let mut models = Vec::new();
         loop {
-            match self.stealer.steal() {
+            let fields = match self.stealer.steal() {
                 Stolen::Empty | Stolen::Abort => continue,
                 Stolen::Data(ChunkWorkerState::Quit) => break,
                 Stolen::Data(ChunkWorkerState::Process(start, end)) => {
-                    let fields = self.parse_chunk(&self.file[start..end]);
-                    if let Some(model) = P::new(self.parse_fields(fields)) {
-                        models.push(Arc::new(model));
-                    }
+                    let mmap = self.file.as_ref().unwrap();
+                    self.parse_chunk(&mmap[start..end], start)?
                 }
+                Stolen::Data(ChunkWorkerState::ProcessOwned(start, bytes)) => {
+                    self.parse_chunk(&bytes, start)?
+                }
+            };
+            if let Some(model) = P::new(self.parse_fields(fields)) {
+                models.push(Arc::new(model));
             }
         }
 
-        return models;
+        Ok(models)
     }
 
-    /// Converts raw chunk bytes to list of lines with multi-line syntax support
-    fn parse_chunk(&self, chunk: &[u8]) -> LinkedList<String> {
+    /// Converts raw chunk bytes to list of lines with multi-line syntax support.
+    /// `base` is the offset of the chunk in the original input, so a UTF-8
+    /// error can be reported at its absolute position.
+    fn parse_chunk(&self, chunk: &[u8], base: usize) -> Result<LinkedList<String>, ParseError> {
         let mut fields = LinkedList::new();
+        let mut offset = base;
 
         // Now we'll process each line of chunk
-        for line in chunk.lines() {
-            let unwrapped_line = line.unwrap();
+        for raw in chunk.split(|byte| *byte == b'\n') {
+            let line_start = offset;
+            // Account for the line and the `\n` separator that was split off
+            offset += raw.len() + 1;
+            // Drop a trailing carriage return so CRLF inputs parse cleanly
+            let raw = match raw.split_last() {
+                Some((b'\r', rest)) => rest,
+                _ => raw,
+            };
+            let unwrapped_line = std::str::from_utf8(raw)
+                .map_err(|err| ParseError::InvalidUtf8 { offset: line_start + err.valid_up_to() })?
+                .to_string();
             // If line is empty (but it shouldn't) - skip
             if unwrapped_line.is_empty() {
                 continue;
             }
 
-            // Keys can have multi-line syntax starting with single space
-            // So we'll process them and concat with previous line in list
-            if unwrapped_line.starts_with(" ") && !fields.is_empty() {
+            // Fields can be folded over several lines: a continuation begins
+            // with a space or a tab (deb822 allows both). Join it with the
+            // previous line, honouring the two folding rules dpkg uses.
+            if (unwrapped_line.starts_with(' ') || unwrapped_line.starts_with('\t'))
+                && !fields.is_empty()
+            {
                 let prev_line = fields.pop_back().unwrap();
-                fields.push_back(format!("{}\n{}", prev_line, unwrapped_line).to_string());
+                // Only the single fold-indicator space/tab is part of the
+                // syntax; any remaining indentation is significant and kept.
+                let folded = unwrapped_line
+                    .strip_prefix(' ')
+                    .or_else(|| unwrapped_line.strip_prefix('\t'))
+                    .unwrap_or(&unwrapped_line);
+                // A line whose content is a single period is a genuinely blank
+                // line inside the value, not a literal dot.
+                if folded == "." {
+                    fields.push_back(format!("{}\n", prev_line));
+                } else {
+                    fields.push_back(format!("{}\n{}", prev_line, folded));
+                }
             } else {
                 fields.push_back(unwrapped_line);
             }
         }
 
-        return fields;
+        Ok(fields)
     }
 
-    /// Parses lines to keys and values
-    fn parse_fields(&self, fields: LinkedList<String>) -> HashMap<String, String> {
-        let mut fields_map = HashMap::new();
+    /// Parses lines to keys and values, preserving the original field order
+    /// so callers can reconstruct a stanza exactly as it was read
+    fn parse_fields(&self, fields: LinkedList<String>) -> IndexMap<String, String> {
+        let mut fields_map = IndexMap::new();
 
         for field in fields {
             // Dpkg uses key-value syntax, so firstly, we'll find delimiter
@@ -168,6 +413,6 @@ impl ChunkWorker {
             }
         }
 
-        return fields_map;
+        fields_map
     }
 }